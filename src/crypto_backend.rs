@@ -0,0 +1,115 @@
+use ring::{agreement, hmac, hkdf, aead, digest, rand};
+use ring::rand::SecureRandom;
+use untrusted::Input;
+use error::WebPushError;
+
+/// The cryptographic primitives `HttpEce` needs: ephemeral P-256 key agreement, HKDF-SHA256 and
+/// AES-128-GCM. Implement this to run the Web Push encryption logic against a crypto library
+/// other than `ring` (e.g. `RustCrypto` on targets where `ring` doesn't build, or a hardware-backed
+/// signer), without touching `http_ece`'s ECE framing.
+pub trait CryptoBackend {
+    /// An ephemeral private key as produced by `generate_keypair`, consumed by `agree_ephemeral`.
+    type KeyPair;
+
+    /// Generates an ephemeral P-256 key pair, returning the private key handle and the encoded
+    /// public key (the uncompressed point, 65 bytes for P-256).
+    fn generate_keypair(&self) -> Result<(Self::KeyPair, Vec<u8>), WebPushError>;
+
+    /// Fills `out` with cryptographically secure random bytes (used for the per-message salt).
+    fn random_bytes(&self, out: &mut [u8]) -> Result<(), WebPushError>;
+
+    /// Computes the ECDH shared secret between `private_key` and `peer_public_key` and hands it to
+    /// `f`. Mirrors `ring`'s `agree_ephemeral`, which never exposes the raw secret other than
+    /// through such a callback.
+    fn agree_ephemeral<R, F>(&self, private_key: Self::KeyPair, peer_public_key: &[u8], f: F) -> Result<R, WebPushError>
+        where F: FnOnce(&[u8]) -> Result<R, WebPushError>;
+
+    /// Combined HKDF-Extract/HKDF-Expand over SHA-256, matching the `ring` `hkdf::extract_and_expand`
+    /// shape already used throughout the ECE key schedule.
+    fn hkdf_sha256(&self, salt: &[u8], secret: &[u8], info: &[u8], out: &mut [u8]);
+
+    /// Seals `in_out` in place with AES-128-GCM: `in_out` holds the plaintext followed by 16 bytes
+    /// of space for the authentication tag, and is rewritten to ciphertext || tag.
+    fn aes_128_gcm_seal(&self, key: &[u8; 16], nonce: &[u8; 12], in_out: &mut [u8]) -> Result<(), WebPushError>;
+
+    /// Opens `in_out` in place with AES-128-GCM: `in_out` holds ciphertext followed by its 16-byte
+    /// tag, and is rewritten to plaintext in its leading bytes. Returns the plaintext length.
+    /// Fails with `WebPushError::Decryption` if the tag doesn't match.
+    fn aes_128_gcm_open(&self, key: &[u8; 16], nonce: &[u8; 12], in_out: &mut [u8]) -> Result<usize, WebPushError>;
+
+    /// Exports `private_key` as raw bytes that `import_private_key` can later turn back into a
+    /// usable `KeyPair`, so a subscription's receiver-side key can be persisted (e.g. alongside
+    /// the subscription in a database) and reused across many incoming messages instead of being
+    /// consumed by a single `agree_ephemeral`/`decrypt` call.
+    ///
+    /// `ring`'s `EphemeralPrivateKey` is deliberately single-use and non-exportable, so the default
+    /// `RingBackend` can't implement this; it returns `WebPushError::NotImplemented`. Backends
+    /// built on a library with a static ECDH key type (e.g. `p256`) can override it to support
+    /// long-lived receiver keys.
+    fn export_private_key(&self, _private_key: &Self::KeyPair) -> Result<Vec<u8>, WebPushError> {
+        Err(WebPushError::NotImplemented("this CryptoBackend's key pairs are single-use and cannot be exported for reuse"))
+    }
+
+    /// The inverse of `export_private_key`: reconstructs a `KeyPair` from previously exported raw
+    /// bytes, so it can be fed into `agree_ephemeral` again for the next message.
+    fn import_private_key(&self, _raw: &[u8]) -> Result<Self::KeyPair, WebPushError> {
+        Err(WebPushError::NotImplemented("this CryptoBackend's key pairs are single-use and cannot be imported"))
+    }
+}
+
+/// The default `CryptoBackend`, backed by `ring`. This is what `HttpEce::new` uses.
+pub struct RingBackend {
+    rng: rand::SystemRandom,
+}
+
+impl Default for RingBackend {
+    fn default() -> Self {
+        RingBackend { rng: rand::SystemRandom::new() }
+    }
+}
+
+impl CryptoBackend for RingBackend {
+    type KeyPair = agreement::EphemeralPrivateKey;
+
+    fn generate_keypair(&self) -> Result<(Self::KeyPair, Vec<u8>), WebPushError> {
+        let private_key     = agreement::EphemeralPrivateKey::generate(&agreement::ECDH_P256, &self.rng)?;
+        let mut public_key  = [0u8; agreement::PUBLIC_KEY_MAX_LEN];
+        let public_key      = &mut public_key[..private_key.public_key_len()];
+
+        private_key.compute_public_key(public_key)?;
+
+        Ok((private_key, public_key.to_vec()))
+    }
+
+    fn random_bytes(&self, out: &mut [u8]) -> Result<(), WebPushError> {
+        self.rng.fill(out)?;
+        Ok(())
+    }
+
+    fn agree_ephemeral<R, F>(&self, private_key: Self::KeyPair, peer_public_key: &[u8], f: F) -> Result<R, WebPushError>
+        where F: FnOnce(&[u8]) -> Result<R, WebPushError>
+    {
+        let peer_input = Input::from(peer_public_key);
+        agreement::agree_ephemeral(private_key, &agreement::ECDH_P256, peer_input, WebPushError::Unspecified, f)
+    }
+
+    fn hkdf_sha256(&self, salt: &[u8], secret: &[u8], info: &[u8], out: &mut [u8]) {
+        let salt_key = hmac::SigningKey::new(&digest::SHA256, salt);
+        hkdf::extract_and_expand(&salt_key, secret, info, out);
+    }
+
+    fn aes_128_gcm_seal(&self, key: &[u8; 16], nonce: &[u8; 12], in_out: &mut [u8]) -> Result<(), WebPushError> {
+        let sealing_key = aead::SealingKey::new(&aead::AES_128_GCM, key)?;
+        aead::seal_in_place(&sealing_key, nonce, "".as_bytes(), in_out, 16)?;
+        Ok(())
+    }
+
+    fn aes_128_gcm_open(&self, key: &[u8; 16], nonce: &[u8; 12], in_out: &mut [u8]) -> Result<usize, WebPushError> {
+        let opening_key = aead::OpeningKey::new(&aead::AES_128_GCM, key)?;
+
+        let plaintext = aead::open_in_place(&opening_key, nonce, "".as_bytes(), 0, in_out)
+            .map_err(|_| WebPushError::Decryption("AES-128-GCM authentication tag did not match"))?;
+
+        Ok(plaintext.len())
+    }
+}
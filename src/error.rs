@@ -0,0 +1,43 @@
+use std::error::Error;
+use std::fmt;
+use ring::error::Unspecified;
+
+/// Errors produced while encrypting or decrypting a Web Push message body.
+#[derive(Debug)]
+pub enum WebPushError {
+    /// An unspecified cryptographic failure, as surfaced by `ring` (key generation, ECDH
+    /// agreement, or AES-GCM sealing/opening failed for a reason `ring` doesn't detail further).
+    Unspecified,
+    /// The configured record size leaves no room for content once the authentication tag and the
+    /// coding's padding overhead are accounted for.
+    ContentTooLong,
+    /// A requested operation isn't supported, either for the chosen content coding or by the
+    /// crypto backend (e.g. exporting or importing single-use private keys).
+    NotImplemented(&'static str),
+    /// Decryption failed: either the input was truncated before a complete header/record, or an
+    /// authentication tag didn't match.
+    Decryption(&'static str),
+}
+
+impl fmt::Display for WebPushError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WebPushError::Unspecified => write!(f, "unspecified cryptographic error"),
+            WebPushError::ContentTooLong => write!(f, "content does not fit into the configured record size"),
+            WebPushError::NotImplemented(reason) => write!(f, "not implemented: {}", reason),
+            WebPushError::Decryption(reason) => write!(f, "decryption failed: {}", reason),
+        }
+    }
+}
+
+impl Error for WebPushError {
+    fn description(&self) -> &str {
+        "a webpush error"
+    }
+}
+
+impl From<Unspecified> for WebPushError {
+    fn from(_: Unspecified) -> WebPushError {
+        WebPushError::Unspecified
+    }
+}
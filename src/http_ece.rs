@@ -1,68 +1,264 @@
-use ring::{hmac, hkdf, agreement, rand, digest, aead};
-use untrusted::Input;
 use error::WebPushError;
 use message::WebPushPayload;
+use crypto_backend::{CryptoBackend, RingBackend};
 
 pub enum ContentCoding {
     AesGcm,
     Aes128Gcm,
 }
 
-pub struct HttpEce<'a> {
+impl ContentCoding {
+    /// The value to send in the `Content-Encoding` header for this coding. `aes128gcm` bodies are
+    /// self-describing (salt and sender key are in the header block), so unlike `aesgcm` they
+    /// don't need the accompanying `Crypto-Key`/`Encryption` headers.
+    pub fn to_str(&self) -> &'static str {
+        match *self {
+            ContentCoding::AesGcm => "aesgcm",
+            ContentCoding::Aes128Gcm => "aes128gcm",
+        }
+    }
+}
+
+/// Bytes of authentication tag appended to every sealed record.
+const TAG_LEN: usize = 16;
+
+/// A push subscription's P-256 key pair and auth secret, as sent to a push service on
+/// subscription and needed again here to decrypt a message addressed to it. Build one with
+/// `generate` for round-trip tests, or a receiver implementation that already has its own key
+/// material can construct one directly.
+pub struct SubscriptionKeys<B: CryptoBackend = RingBackend> {
+    pub private_key: B::KeyPair,
+    pub public_key: Vec<u8>,
+    pub auth_secret: [u8; 16],
+}
+
+impl<B: CryptoBackend + Default> SubscriptionKeys<B> {
+    /// Generates a fresh P-256 key pair and 16-byte auth secret for a push subscription.
+    pub fn generate() -> Result<SubscriptionKeys<B>, WebPushError> {
+        let backend = B::default();
+        let (private_key, public_key) = backend.generate_keypair()?;
+
+        let mut auth_secret = [0u8; 16];
+        backend.random_bytes(&mut auth_secret)?;
+
+        Ok(SubscriptionKeys { private_key, public_key, auth_secret })
+    }
+}
+
+impl<B: CryptoBackend> SubscriptionKeys<B> {
+    pub fn public_key_base64(&self) -> String {
+        base64::encode_config(&self.public_key, base64::URL_SAFE_NO_PAD)
+    }
+
+    pub fn auth_secret_base64(&self) -> String {
+        base64::encode_config(&self.auth_secret, base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Exports `private_key` as raw bytes that can be persisted and later turned back into a
+    /// `SubscriptionKeys` (via `HttpEce::decrypt_with_exported_key`) without keeping this
+    /// single-use `private_key` around, so a subscription's key can be reused across many
+    /// incoming messages. See `CryptoBackend::export_private_key` for backend support.
+    pub fn export_private_key(&self, backend: &B) -> Result<Vec<u8>, WebPushError> {
+        backend.export_private_key(&self.private_key)
+    }
+}
+
+pub struct HttpEce<'a, B: CryptoBackend = RingBackend> {
     peer_public_key: &'a [u8],
     peer_secret: &'a [u8],
     coding: ContentCoding,
-    rng: rand::SystemRandom,
+    backend: B,
+    rs: u32,
 }
 
-impl<'a> HttpEce<'a> {
-    pub fn new(coding: ContentCoding, peer_public_key: &'a [u8], peer_secret: &'a [u8]) -> Result<HttpEce<'a>, WebPushError> {
+impl<'a> HttpEce<'a, RingBackend> {
+    /// `rs` is the record size to chunk the content into, as described in RFC 8188. Pass `None`
+    /// for the commonly used default of 4096 bytes.
+    ///
+    /// Uses `ring` for the underlying crypto. To run against a different backend (`RustCrypto`, a
+    /// hardware-backed signer, ...), implement `CryptoBackend` and construct with `with_backend`.
+    pub fn new(coding: ContentCoding, peer_public_key: &'a [u8], peer_secret: &'a [u8], rs: Option<u32>) -> Result<HttpEce<'a, RingBackend>, WebPushError> {
+        HttpEce::with_backend(coding, peer_public_key, peer_secret, rs, RingBackend::default())
+    }
+}
+
+impl<'a, B: CryptoBackend> HttpEce<'a, B> {
+    pub fn with_backend(coding: ContentCoding, peer_public_key: &'a [u8], peer_secret: &'a [u8], rs: Option<u32>, backend: B)
+                         -> Result<HttpEce<'a, B>, WebPushError> {
+        let rs = rs.unwrap_or(4096);
+
+        // `rs` has to leave room for at least one byte of content once the authentication tag and
+        // the coding's padding overhead are accounted for, or `seal_records` could never fit a
+        // record.
+        if (rs as usize) <= TAG_LEN + padding_overhead(&coding) {
+            return Err(WebPushError::ContentTooLong);
+        }
+
         Ok(HttpEce {
-            rng: rand::SystemRandom::new(),
             peer_public_key: peer_public_key,
             peer_secret: peer_secret,
             coding: coding,
+            backend: backend,
+            rs: rs,
         })
     }
 
     pub fn encrypt(&self, content: &'a [u8]) -> Result<WebPushPayload, WebPushError> {
-        if content.len() > 3800 { return Err(WebPushError::ContentTooLong) }
-
-        let private_key        = agreement::EphemeralPrivateKey::generate(&agreement::ECDH_P256, &self.rng)?;
-        let mut public_key     = [0u8; agreement::PUBLIC_KEY_MAX_LEN];
-        let public_key         = &mut public_key[..private_key.public_key_len()];
-        let agr                = &agreement::ECDH_P256;
-        let mut salt_bytes     = [0u8; 16];
-        let peer_input         = Input::from(self.peer_public_key);
-
-        self.rng.fill(&mut salt_bytes)?;
-        private_key.compute_public_key(public_key)?;
+        let (private_key, public_key) = self.backend.generate_keypair()?;
+        let mut salt_bytes = [0u8; 16];
+        self.backend.random_bytes(&mut salt_bytes)?;
 
-        agreement::agree_ephemeral(private_key, agr, peer_input, WebPushError::Unspecified, |shared_secret| {
+        self.backend.agree_ephemeral(private_key, self.peer_public_key, |shared_secret| {
             match self.coding {
                 ContentCoding::AesGcm => {
-                    let mut payload = [0u8; 3818];
-                    front_pad(content, &mut payload);
+                    let (cek, nonce) = self.aes_gcm_key(shared_secret, &public_key, &salt_bytes);
+                    let records = self.seal_records(content, &cek, &nonce, padding_overhead(&self.coding))?;
 
-                    self.aes_gcm(shared_secret, public_key, &salt_bytes, &mut payload)?;
+                    Ok(WebPushPayload {
+                        content: records,
+                        public_key: public_key.clone(),
+                        salt: salt_bytes.to_vec(),
+                    })
+                },
+                ContentCoding::Aes128Gcm => {
+                    let (cek, nonce) = self.aes_128_gcm_key(shared_secret, &public_key, &salt_bytes);
+                    let records = self.seal_records(content, &cek, &nonce, padding_overhead(&self.coding))?;
+
+                    let mut body = Vec::with_capacity(16 + 4 + 1 + public_key.len() + records.len());
+                    body.extend_from_slice(&salt_bytes);
+                    body.push((self.rs >> 24) as u8);
+                    body.push((self.rs >> 16) as u8);
+                    body.push((self.rs >> 8) as u8);
+                    body.push(self.rs as u8);
+                    body.push(public_key.len() as u8);
+                    body.extend_from_slice(&public_key);
+                    body.extend_from_slice(&records);
 
                     Ok(WebPushPayload {
-                        content: payload.to_vec(),
-                        public_key: public_key.to_vec(),
+                        content: body,
+                        public_key: public_key.clone(),
                         salt: salt_bytes.to_vec(),
                     })
                 },
-                ContentCoding::Aes128Gcm =>
-                    Err(WebPushError::NotImplemented("Aes128Gcm support comes when enough browsers implement it")),
             }
         })
     }
 
-    fn aes_gcm(&self, shared_secret: &'a [u8], as_public_key: &'a [u8], salt_bytes: &'a [u8], mut payload: &'a mut [u8])
-               -> Result<(), WebPushError> {
-        let salt               = hmac::SigningKey::new(&digest::SHA256, salt_bytes);
-        let client_auth_secret = hmac::SigningKey::new(&digest::SHA256, self.peer_secret);
+    /// Reverses `encrypt` for the self-describing `aes128gcm` coding: `private_key` is the
+    /// subscription's private key (paired with the public key this `HttpEce` was constructed
+    /// with), and the auth secret is taken from `self` the same way it is for encryption.
+    /// `aesgcm` isn't supported here since its salt and sender public key travel in the
+    /// `Crypto-Key`/`Encryption` headers rather than the body.
+    pub fn decrypt(&self, private_key: B::KeyPair, body: &[u8]) -> Result<Vec<u8>, WebPushError> {
+        match self.coding {
+            ContentCoding::AesGcm =>
+                Err(WebPushError::NotImplemented("Decrypting aesgcm needs the out-of-band Crypto-Key/Encryption headers, which this method doesn't receive")),
+            ContentCoding::Aes128Gcm => {
+                if body.len() < 21 { return Err(WebPushError::Decryption("aes128gcm body is shorter than its header")) }
+
+                let salt_bytes = &body[0..16];
+                let rs = ((body[16] as usize) << 24) | ((body[17] as usize) << 16) | ((body[18] as usize) << 8) | (body[19] as usize);
+                let idlen = body[20] as usize;
+
+                if body.len() < 21 + idlen { return Err(WebPushError::Decryption("aes128gcm header is missing the sender's public key")) }
+
+                let sender_public_key = &body[21..21 + idlen];
+                let records = &body[21 + idlen..];
+
+                self.backend.agree_ephemeral(private_key, sender_public_key, |shared_secret| {
+                    let (cek, nonce) = self.aes_128_gcm_key(shared_secret, sender_public_key, salt_bytes);
+                    self.open_records(records, rs, &cek, &nonce)
+                })
+            },
+        }
+    }
+
+    /// Like `decrypt`, but takes a private key previously persisted via
+    /// `SubscriptionKeys::export_private_key` instead of consuming the `KeyPair` directly. This is
+    /// the path a long-lived receiver should use: the same exported bytes can be fed in again for
+    /// the subscription's next message, rather than generating a fresh single-use key per message.
+    pub fn decrypt_with_exported_key(&self, exported_private_key: &[u8], body: &[u8]) -> Result<Vec<u8>, WebPushError> {
+        let private_key = self.backend.import_private_key(exported_private_key)?;
+        self.decrypt(private_key, body)
+    }
+
+    /// Reverses `seal_records` for `aes128gcm`: splits `records` back into `rs`-sized chunks,
+    /// opens each one with its sequential nonce, and strips the trailing padding delimiter.
+    fn open_records(&self, records: &[u8], rs: usize, cek: &[u8; 16], nonce: &[u8; 12]) -> Result<Vec<u8>, WebPushError> {
+        if rs <= TAG_LEN { return Err(WebPushError::Decryption("aes128gcm record size header is too small")) }
+        if records.is_empty() { return Err(WebPushError::Decryption("aes128gcm body has no records")) }
+
+        let mut plaintext = Vec::with_capacity(records.len());
+        let mut offset = 0;
+        let mut seq = 0u64;
+
+        while offset < records.len() {
+            let end = (offset + rs).min(records.len());
+            let is_last = end == records.len();
+            let mut record = records[offset..end].to_vec();
+
+            if record.len() <= TAG_LEN { return Err(WebPushError::Decryption("aes128gcm record is shorter than the authentication tag")) }
+
+            let record_nonce = sequential_nonce(nonce, seq);
+            let plaintext_len = self.backend.aes_128_gcm_open(cek, &record_nonce, &mut record)?;
+            let record_plain = &record[..plaintext_len];
+
+            if record_plain.is_empty() { return Err(WebPushError::Decryption("aes128gcm record has no padding delimiter")) }
 
+            let delimiter = record_plain[record_plain.len() - 1];
+            match (delimiter, is_last) {
+                (0x02, true) | (0x01, false) => {},
+                _ => return Err(WebPushError::Decryption("aes128gcm record has an unexpected padding delimiter")),
+            }
+
+            plaintext.extend_from_slice(&record_plain[..record_plain.len() - 1]);
+            offset = end;
+            seq += 1;
+        }
+
+        Ok(plaintext)
+    }
+
+    /// Splits `content` into `rs`-sized records, pads and seals each one independently with its
+    /// own nonce (the shared `nonce` XORed with the big-endian record sequence number), and
+    /// concatenates the results. `padding_overhead` is the per-record padding cost on top of the
+    /// authentication tag: 2 bytes for the `aesgcm` front-padding length prefix, 1 byte for the
+    /// `aes128gcm` trailing delimiter.
+    fn seal_records(&self, content: &[u8], cek: &[u8; 16], nonce: &[u8; 12], padding_overhead: usize)
+                     -> Result<Vec<u8>, WebPushError> {
+        let capacity = (self.rs as usize).saturating_sub(TAG_LEN + padding_overhead);
+        if capacity == 0 { return Err(WebPushError::ContentTooLong) }
+
+        let chunks: Vec<&[u8]> = if content.is_empty() {
+            vec![&content[..]]
+        } else {
+            content.chunks(capacity).collect()
+        };
+
+        let mut output = Vec::with_capacity(chunks.len() * self.rs as usize);
+
+        for (i, chunk) in chunks.iter().enumerate() {
+            let is_last = i == chunks.len() - 1;
+            let mut record = vec![0u8; chunk.len() + padding_overhead + TAG_LEN];
+
+            match self.coding {
+                ContentCoding::AesGcm => front_pad(chunk, &mut record),
+                ContentCoding::Aes128Gcm => {
+                    record[..chunk.len()].copy_from_slice(chunk);
+                    record[chunk.len()] = if is_last { 0x02 } else { 0x01 };
+                },
+            }
+
+            let record_nonce = sequential_nonce(nonce, i as u64);
+            self.backend.aes_128_gcm_seal(cek, &record_nonce, &mut record)?;
+
+            output.extend_from_slice(&record);
+        }
+
+        Ok(output)
+    }
+
+    fn aes_gcm_key(&self, shared_secret: &[u8], as_public_key: &[u8], salt_bytes: &[u8]) -> ([u8; 16], [u8; 12]) {
         let mut context = Vec::with_capacity(140);
         context.extend_from_slice("P-256\0".as_bytes());
         context.push((self.peer_public_key.len() >> 8) as u8);
@@ -73,28 +269,68 @@ impl<'a> HttpEce<'a> {
         context.extend_from_slice(as_public_key);
 
         let mut ikm = [0u8; 32];
-        hkdf::extract_and_expand(&client_auth_secret, &shared_secret, "Content-Encoding: auth\0".as_bytes(), &mut ikm);
+        self.backend.hkdf_sha256(self.peer_secret, shared_secret, "Content-Encoding: auth\0".as_bytes(), &mut ikm);
 
         let mut cek_info = Vec::with_capacity(165);
         cek_info.extend_from_slice("Content-Encoding: aesgcm\0".as_bytes());
         cek_info.extend_from_slice(&context);
 
         let mut content_encryption_key = [0u8; 16];
-        hkdf::extract_and_expand(&salt, &ikm, &cek_info, &mut content_encryption_key);
+        self.backend.hkdf_sha256(salt_bytes, &ikm, &cek_info, &mut content_encryption_key);
 
         let mut nonce_info = Vec::with_capacity(164);
         nonce_info.extend_from_slice("Content-Encoding: nonce\0".as_bytes());
         nonce_info.extend_from_slice(&context);
 
         let mut nonce = [0u8; 12];
-        hkdf::extract_and_expand(&salt, &ikm, &nonce_info, &mut nonce);
+        self.backend.hkdf_sha256(salt_bytes, &ikm, &nonce_info, &mut nonce);
+
+        (content_encryption_key, nonce)
+    }
+
+    /// Key schedule for the `aes128gcm` content coding (RFC 8188 / RFC 8291), as opposed to the
+    /// `aesgcm` draft handled by `aes_gcm_key` above: the shared secret is first run through HKDF
+    /// keyed on the subscription's auth secret to get a coding-agnostic IKM, which is then
+    /// re-expanded with the per-message salt to get the content encryption key and nonce.
+    fn aes_128_gcm_key(&self, shared_secret: &[u8], as_public_key: &[u8], salt_bytes: &[u8]) -> ([u8; 16], [u8; 12]) {
+        let mut key_info = Vec::with_capacity(14 + 65 + 65);
+        key_info.extend_from_slice("WebPush: info\0".as_bytes());
+        key_info.extend_from_slice(self.peer_public_key);
+        key_info.extend_from_slice(as_public_key);
+
+        let mut ikm = [0u8; 32];
+        self.backend.hkdf_sha256(self.peer_secret, shared_secret, &key_info, &mut ikm);
+
+        let mut content_encryption_key = [0u8; 16];
+        self.backend.hkdf_sha256(salt_bytes, &ikm, "Content-Encoding: aes128gcm\0".as_bytes(), &mut content_encryption_key);
+
+        let mut nonce = [0u8; 12];
+        self.backend.hkdf_sha256(salt_bytes, &ikm, "Content-Encoding: nonce\0".as_bytes(), &mut nonce);
+
+        (content_encryption_key, nonce)
+    }
+}
+
+/// The per-record padding cost on top of the authentication tag: 2 bytes for the `aesgcm`
+/// front-padding length prefix, 1 byte for the `aes128gcm` trailing delimiter.
+fn padding_overhead(coding: &ContentCoding) -> usize {
+    match *coding {
+        ContentCoding::AesGcm => 2,
+        ContentCoding::Aes128Gcm => 1,
+    }
+}
 
-        let sealing_key = aead::SealingKey::new(&aead::AES_128_GCM, &content_encryption_key)?;
-        aead::seal_in_place(&sealing_key, &nonce, "".as_bytes(), &mut payload, 16)?;
+/// Derives the nonce for record `seq` by XORing it into the low-order bytes of the base nonce, as
+/// described in RFC 8188 section 3.3.
+fn sequential_nonce(base: &[u8; 12], seq: u64) -> [u8; 12] {
+    let mut nonce = *base;
+    let seq_bytes = seq.to_be_bytes();
 
-        Ok(())
+    for i in 0..8 {
+        nonce[4 + i] ^= seq_bytes[i];
     }
 
+    nonce
 }
 
 fn front_pad(payload: &[u8], output: &mut [u8]) {
@@ -109,3 +345,33 @@ fn front_pad(payload: &[u8], output: &mut [u8]) {
         output[padding_size + i + 2] = payload[i];
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aes128gcm_round_trips_a_single_record() {
+        let keys = SubscriptionKeys::<RingBackend>::generate().unwrap();
+        let ece = HttpEce::new(ContentCoding::Aes128Gcm, &keys.public_key, &keys.auth_secret, None).unwrap();
+
+        let content = b"a single small record";
+        let payload = ece.encrypt(content).unwrap();
+        let plaintext = ece.decrypt(keys.private_key, &payload.content).unwrap();
+
+        assert_eq!(content.to_vec(), plaintext);
+    }
+
+    #[test]
+    fn aes128gcm_round_trips_multiple_records() {
+        let keys = SubscriptionKeys::<RingBackend>::generate().unwrap();
+        // A tiny record size forces `content` to be split across several records.
+        let ece = HttpEce::new(ContentCoding::Aes128Gcm, &keys.public_key, &keys.auth_secret, Some(32)).unwrap();
+
+        let content = b"this content is long enough to need several 32-byte records";
+        let payload = ece.encrypt(content).unwrap();
+        let plaintext = ece.decrypt(keys.private_key, &payload.content).unwrap();
+
+        assert_eq!(content.to_vec(), plaintext);
+    }
+}
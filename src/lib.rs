@@ -0,0 +1,13 @@
+extern crate ring;
+extern crate untrusted;
+extern crate base64;
+
+pub mod error;
+pub mod message;
+pub mod http_ece;
+pub mod crypto_backend;
+
+pub use error::WebPushError;
+pub use message::WebPushPayload;
+pub use http_ece::{ContentCoding, HttpEce, SubscriptionKeys};
+pub use crypto_backend::{CryptoBackend, RingBackend};
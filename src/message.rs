@@ -0,0 +1,9 @@
+/// An encrypted Web Push message body, ready to be sent as the request body of a push to an
+/// endpoint. For `aes128gcm`, `content` is fully self-describing (it embeds `salt` and
+/// `public_key` in its header); `aesgcm` needs `public_key` and `salt` sent separately in the
+/// `Crypto-Key` and `Encryption` headers.
+pub struct WebPushPayload {
+    pub content: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub salt: Vec<u8>,
+}